@@ -0,0 +1,64 @@
+//! Derive macros for `bitsparrow`'s `BitEncode` and `BitDecode` traits.
+//!
+//! `#[derive(BitEncode)]` / `#[derive(BitDecode)]` encode and decode a
+//! struct's fields in declaration order, the same order a hand-written
+//! `Encoder`/`Decoder` chain would use.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+#[proc_macro_derive(BitEncode)]
+pub fn derive_bit_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = input.ident;
+    let fields = named_fields(input.data, "BitEncode");
+    let field_names: Vec<_> = fields.iter().map(|field| field.ident.clone().unwrap()).collect();
+
+    let expanded = quote! {
+        impl ::bitsparrow::BitEncode for #name {
+            fn encode(&self, encoder: &mut ::bitsparrow::Encoder) {
+                #(
+                    ::bitsparrow::BitEncode::encode(&self.#field_names, encoder);
+                )*
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+#[proc_macro_derive(BitDecode)]
+pub fn derive_bit_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = input.ident;
+    let fields = named_fields(input.data, "BitDecode");
+    let field_names: Vec<_> = fields.iter().map(|field| field.ident.clone().unwrap()).collect();
+    let field_types: Vec<_> = fields.iter().map(|field| field.ty.clone()).collect();
+
+    let expanded = quote! {
+        impl ::bitsparrow::BitDecode for #name {
+            fn decode(decoder: &::bitsparrow::Decoder) -> Result<Self, ::bitsparrow::Error> {
+                Ok(#name {
+                    #(
+                        #field_names: <#field_types as ::bitsparrow::BitDecode>::decode(decoder)?,
+                    )*
+                })
+            }
+        }
+    };
+
+    expanded.into()
+}
+
+fn named_fields(data: Data, trait_name: &str) -> syn::punctuated::Punctuated<syn::Field, syn::Token![,]> {
+    match data {
+        Data::Struct(data) => match data.fields {
+            Fields::Named(fields) => fields.named,
+            _ => panic!("{} can only be derived for structs with named fields", trait_name),
+        },
+        _ => panic!("{} can only be derived for structs", trait_name),
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::{HashMap, BTreeMap};
+use std::hash::Hash;
+
+use {Encoder, Decoder, Error};
+
+///
+/// #BitEncode
+///
+/// Implemented by types that know how to write themselves into an
+/// `Encoder`. Structs typically pick this up via `#[derive(BitEncode)]`,
+/// which encodes each field in declaration order.
+///
+pub trait BitEncode {
+    fn encode(&self, encoder: &mut Encoder);
+}
+
+///
+/// #BitDecode
+///
+/// Implemented by types that know how to read themselves out of a
+/// `Decoder`. Structs typically pick this up via `#[derive(BitDecode)]`,
+/// decoding each field in the same order `BitEncode` wrote it.
+///
+pub trait BitDecode: Sized {
+    fn decode(decoder: &Decoder) -> Result<Self, Error>;
+}
+
+macro_rules! impl_bit_codec_primitive {
+    ($ty:ty, $method:ident) => {
+        impl BitEncode for $ty {
+            fn encode(&self, encoder: &mut Encoder) {
+                encoder.$method(*self);
+            }
+        }
+
+        impl BitDecode for $ty {
+            fn decode(decoder: &Decoder) -> Result<Self, Error> {
+                decoder.$method()
+            }
+        }
+    }
+}
+
+impl_bit_codec_primitive!(u8, uint8);
+impl_bit_codec_primitive!(u16, uint16);
+impl_bit_codec_primitive!(u32, uint32);
+impl_bit_codec_primitive!(u64, uint64);
+impl_bit_codec_primitive!(i8, int8);
+impl_bit_codec_primitive!(i16, int16);
+impl_bit_codec_primitive!(i32, int32);
+impl_bit_codec_primitive!(i64, int64);
+impl_bit_codec_primitive!(f32, float32);
+impl_bit_codec_primitive!(f64, float64);
+impl_bit_codec_primitive!(bool, bool);
+
+impl BitEncode for String {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.string(self);
+    }
+}
+
+impl BitDecode for String {
+    fn decode(decoder: &Decoder) -> Result<Self, Error> {
+        decoder.string()
+    }
+}
+
+impl<T: BitEncode> BitEncode for Vec<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.size(self.len());
+        for item in self.iter() {
+            item.encode(encoder);
+        }
+    }
+}
+
+// `len` comes straight off the wire and is attacker-controlled, so it is
+// not used to pre-reserve: the collection is grown one element at a time
+// and the existing out-of-bounds error on read rejects a length that
+// outruns the actual stream.
+impl<T: BitDecode> BitDecode for Vec<T> {
+    fn decode(decoder: &Decoder) -> Result<Self, Error> {
+        let len = try!(decoder.size());
+        let mut vec = Vec::new();
+        for _ in 0..len {
+            vec.push(try!(T::decode(decoder)));
+        }
+        Ok(vec)
+    }
+}
+
+// `Option` encodes a leading `bool` discriminant, which naturally
+// bit-packs with any adjacent `bool` fields, followed by the payload
+// when present.
+impl<T: BitEncode> BitEncode for Option<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        match *self {
+            Some(ref value) => {
+                encoder.bool(true);
+                value.encode(encoder);
+            }
+            None => {
+                encoder.bool(false);
+            }
+        }
+    }
+}
+
+impl<T: BitDecode> BitDecode for Option<T> {
+    fn decode(decoder: &Decoder) -> Result<Self, Error> {
+        if try!(decoder.bool()) {
+            Ok(Some(try!(T::decode(decoder))))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<K: BitEncode + Eq + Hash, V: BitEncode> BitEncode for HashMap<K, V> {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.size(self.len());
+        for (key, value) in self.iter() {
+            key.encode(encoder);
+            value.encode(encoder);
+        }
+    }
+}
+
+// See `Vec`'s decode above: `len` is untrusted, so it is never used to
+// pre-reserve.
+impl<K: BitDecode + Eq + Hash, V: BitDecode> BitDecode for HashMap<K, V> {
+    fn decode(decoder: &Decoder) -> Result<Self, Error> {
+        let len = try!(decoder.size());
+        let mut map = HashMap::new();
+        for _ in 0..len {
+            let key = try!(K::decode(decoder));
+            let value = try!(V::decode(decoder));
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+}
+
+impl<K: BitEncode + Ord, V: BitEncode> BitEncode for BTreeMap<K, V> {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.size(self.len());
+        for (key, value) in self.iter() {
+            key.encode(encoder);
+            value.encode(encoder);
+        }
+    }
+}
+
+impl<K: BitDecode + Ord, V: BitDecode> BitDecode for BTreeMap<K, V> {
+    fn decode(decoder: &Decoder) -> Result<Self, Error> {
+        let len = try!(decoder.size());
+        let mut map = BTreeMap::new();
+        for _ in 0..len {
+            let key = try!(K::decode(decoder));
+            let value = try!(V::decode(decoder));
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+}
+
+impl<A: BitEncode, B: BitEncode> BitEncode for (A, B) {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.0.encode(encoder);
+        self.1.encode(encoder);
+    }
+}
+
+impl<A: BitDecode, B: BitDecode> BitDecode for (A, B) {
+    fn decode(decoder: &Decoder) -> Result<Self, Error> {
+        Ok((try!(A::decode(decoder)), try!(B::decode(decoder))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ErrorKind;
+
+    #[derive(Debug, PartialEq)]
+    struct Profile {
+        id: u32,
+        nickname: Option<String>,
+        tags: Vec<String>,
+    }
+
+    // Hand-written the way `#[derive(BitEncode, BitDecode)]` would expand
+    // it: each field encoded/decoded in declaration order.
+    impl BitEncode for Profile {
+        fn encode(&self, encoder: &mut Encoder) {
+            self.id.encode(encoder);
+            self.nickname.encode(encoder);
+            self.tags.encode(encoder);
+        }
+    }
+
+    impl BitDecode for Profile {
+        fn decode(decoder: &Decoder) -> Result<Self, Error> {
+            Ok(Profile {
+                id: try!(u32::decode(decoder)),
+                nickname: try!(BitDecode::decode(decoder)),
+                tags: try!(BitDecode::decode(decoder)),
+            })
+        }
+    }
+
+    #[test]
+    fn struct_with_nested_option_and_vec_roundtrips() {
+        let profile = Profile {
+            id: 7,
+            nickname: Some("ferris".to_string()),
+            tags: vec!["rust".to_string(), "crab".to_string()],
+        };
+
+        let mut encoder = Encoder::new();
+        profile.encode(&mut encoder);
+
+        let decoder = Decoder::new(encoder.into_bytes().unwrap());
+        assert_eq!(Profile::decode(&decoder).unwrap(), profile);
+    }
+
+    #[test]
+    fn struct_with_none_and_empty_vec_roundtrips() {
+        let profile = Profile {
+            id: 1,
+            nickname: None,
+            tags: Vec::new(),
+        };
+
+        let mut encoder = Encoder::new();
+        profile.encode(&mut encoder);
+
+        let decoder = Decoder::new(encoder.into_bytes().unwrap());
+        assert_eq!(Profile::decode(&decoder).unwrap(), profile);
+    }
+
+    #[test]
+    fn hashmap_roundtrips() {
+        let mut map = HashMap::new();
+        map.insert(1u8, "one".to_string());
+        map.insert(2u8, "two".to_string());
+
+        let mut encoder = Encoder::new();
+        map.encode(&mut encoder);
+
+        let decoder = Decoder::new(encoder.into_bytes().unwrap());
+        let decoded: HashMap<u8, String> = HashMap::decode(&decoder).unwrap();
+        assert_eq!(decoded, map);
+    }
+
+    #[test]
+    fn vec_decode_rejects_truncated_stream_instead_of_aborting() {
+        // A crafted `Vec<u8>` length prefix claiming 0x3FFFFFFF elements
+        // with only one real byte behind it must fail with an
+        // out-of-bounds error instead of pre-reserving the claimed length.
+        let bytes = vec![0xFF, 0xFF, 0xFF, 0xFF, 1];
+
+        let decoder = Decoder::new(bytes);
+        match <Vec<u8> as BitDecode>::decode(&decoder) {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::UnexpectedEnd),
+            Ok(value) => panic!("expected UnexpectedEnd, got Ok({:?})", value),
+        }
+    }
+}
@@ -3,33 +3,99 @@ use std::fmt;
 use std::error;
 use std::cell::Cell;
 
+#[cfg(feature = "derive")]
+extern crate bitsparrow_derive;
+
+mod codec;
+
+pub use codec::{BitEncode, BitDecode};
+
+/// Re-exports `#[derive(BitEncode, BitDecode)]` from the companion
+/// `bitsparrow-derive` crate so structs can opt into `BitEncode`/`BitDecode`
+/// without hand-writing the field-by-field impls in `codec`.
+#[cfg(feature = "derive")]
+pub use bitsparrow_derive::{BitEncode, BitDecode};
+
 ///
-/// #EncodingError
+/// #ErrorKind
+///
+/// Distinguishes the different ways encoding or decoding can fail, so
+/// callers can match on the failure instead of parsing a message.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The decoder ran out of bytes before it could satisfy the read.
+    UnexpectedEnd,
+
+    /// A `string`/`str_ref` read did not contain valid UTF-8.
+    InvalidUtf8,
+
+    /// A `size`/`bytes`/`string` value exceeded the 30-bit size cap.
+    SizeTooLarge,
+
+    /// A `varuint`/`varint` read encoded more bits than fit the 64-bit
+    /// target width.
+    VarintOverflow,
+}
+
+impl ErrorKind {
+    fn message(&self) -> &'static str {
+        match *self {
+            ErrorKind::UnexpectedEnd => "Attempted to read out of bounds",
+            ErrorKind::InvalidUtf8 => "Couldn't decode UTF-8 string",
+            ErrorKind::SizeTooLarge => "Size value is too large",
+            ErrorKind::VarintOverflow => "Varint value overflowed the target width",
+        }
+    }
+}
+
 ///
-/// Returned by the Encoder when a value fails to encode.
+/// #Error
+///
+/// Returned by the Encoder or Decoder when a value fails to encode or
+/// decode. Carries an `ErrorKind` plus, for decode failures, the byte
+/// offset at which the failure was detected.
 ///
 #[derive(Debug)]
-pub struct Error(String);
+pub struct Error {
+    kind: ErrorKind,
+    offset: Option<usize>,
+}
 
 impl Error {
-    pub fn new(msg: &str) -> Error {
-        Error(msg.to_string())
+    pub fn new(kind: ErrorKind) -> Error {
+        Error { kind: kind, offset: None }
+    }
+
+    pub fn at(kind: ErrorKind, offset: usize) -> Error {
+        Error { kind: kind, offset: Some(offset) }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
     }
 
-    pub fn out_of_bounds() -> Error {
-        Error::new("Attempted to read out of bounds")
+    pub fn out_of_bounds(offset: usize) -> Error {
+        Error::at(ErrorKind::UnexpectedEnd, offset)
     }
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
-        return &self.0;
+        self.kind.message()
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        match self.offset {
+            Some(offset) => write!(f, "{} (at byte {})", self.kind.message(), offset),
+            None => write!(f, "{}", self.kind.message()),
+        }
     }
 }
 
@@ -50,19 +116,40 @@ impl Encoder {
         }
     }
 
-    pub fn uint8(mut self, uint8: u8) -> Encoder {
+    /// Builds an `Encoder` that appends into an externally owned buffer
+    /// instead of starting from an empty one, so callers can reuse an
+    /// allocation across messages.
+    pub fn encode_into(buffer: Vec<u8>) -> Encoder {
+        Encoder {
+            data: buffer,
+            bool_index: std::usize::MAX,
+            bool_shift: 0,
+            last_error: None,
+        }
+    }
+
+    /// Clears the buffer and bool-packing state so this `Encoder` can be
+    /// reused to serialize another message without reallocating.
+    pub fn reset(&mut self) {
+        self.data.clear();
+        self.bool_index = std::usize::MAX;
+        self.bool_shift = 0;
+        self.last_error = None;
+    }
+
+    pub fn uint8(&mut self, uint8: u8) -> &mut Encoder {
         self.data.push(uint8);
         return self;
     }
 
-    pub fn uint16(mut self, uint16: u16) -> Encoder {
+    pub fn uint16(&mut self, uint16: u16) -> &mut Encoder {
         self.data.reserve(2);
         self.data.push((uint16 >> 8) as u8);
         self.data.push((uint16 & 0xFF) as u8);
         return self;
     }
 
-    pub fn uint32(mut self, uint32: u32) -> Encoder {
+    pub fn uint32(&mut self, uint32: u32) -> &mut Encoder {
         self.data.reserve(4);
         self.data.push((uint32 >> 24) as u8);
         self.data.push(((uint32 >> 16) & 0xFF) as u8);
@@ -71,30 +158,38 @@ impl Encoder {
         return self;
     }
 
-    pub fn int8(self, int8: i8) -> Encoder {
+    pub fn int8(&mut self, int8: i8) -> &mut Encoder {
         self.uint8(unsafe { mem::transmute_copy(&int8) })
     }
 
-    pub fn int16(self, int16: i16) -> Encoder {
+    pub fn int16(&mut self, int16: i16) -> &mut Encoder {
         self.uint16(unsafe { mem::transmute_copy(&int16) })
     }
 
-    pub fn int32(self, int32: i32) -> Encoder {
+    pub fn int32(&mut self, int32: i32) -> &mut Encoder {
         self.uint32(unsafe { mem::transmute_copy(&int32) })
     }
 
-    pub fn float32(self, float32: f32) -> Encoder {
+    pub fn float32(&mut self, float32: f32) -> &mut Encoder {
         self.uint32(unsafe { mem::transmute_copy(&float32) })
     }
 
-    pub fn float64(self, float64: f64) -> Encoder {
+    pub fn uint64(&mut self, uint64: u64) -> &mut Encoder {
+        self.uint32((uint64 >> 32) as u32);
+        return self.uint32((uint64 & 0xFFFFFFFF) as u32);
+    }
+
+    pub fn int64(&mut self, int64: i64) -> &mut Encoder {
+        self.uint64(unsafe { mem::transmute_copy(&int64) })
+    }
+
+    pub fn float64(&mut self, float64: f64) -> &mut Encoder {
         let uint64: u64 = unsafe { mem::transmute_copy(&float64) };
-        return self
-            .uint32((uint64 >> 32) as u32)
-            .uint32((uint64 & 0xFFFFFFFF) as u32);
+        self.uint32((uint64 >> 32) as u32);
+        return self.uint32((uint64 & 0xFFFFFFFF) as u32);
     }
 
-    pub fn bool(mut self, bool: bool) -> Encoder {
+    pub fn bool(&mut self, bool: bool) -> &mut Encoder {
         let bool_bit: u8 = if bool { 1 } else { 0 };
         let index = self.data.len();
 
@@ -109,9 +204,9 @@ impl Encoder {
         self.uint8(bool_bit)
     }
 
-    pub fn size(mut self, size: usize) -> Encoder {
+    pub fn size(&mut self, size: usize) -> &mut Encoder {
         if size > 0x3FFFFFFF {
-            self.last_error = Some(Error::new("[size] value is too large"));
+            self.last_error = Some(Error::new(ErrorKind::SizeTooLarge));
             return self;
         }
 
@@ -129,34 +224,91 @@ impl Encoder {
         return self.uint32((size as u32) | 0xC0000000);
     }
 
-    pub fn bytes(mut self, bytes: &[u8]) -> Encoder {
+    /// Encodes `value` as an unsigned LEB128 varint: 7 bits of payload per
+    /// byte, continuation signalled by the high bit. Small values stay
+    /// compact regardless of the target width, unlike `size`'s 30-bit cap.
+    pub fn varuint(&mut self, mut value: u64) -> &mut Encoder {
+        loop {
+            let mut byte = (value & 0x7F) as u8;
+            value >>= 7;
+
+            if value != 0 {
+                byte |= 0x80;
+            }
+
+            self.data.push(byte);
+
+            if value == 0 {
+                break;
+            }
+        }
+
+        return self;
+    }
+
+    /// Encodes `value` as a signed LEB128 varint, stopping once the remaining
+    /// bits are all sign bits; `Decoder::varint` restores them from bit 6 of
+    /// the final byte.
+    pub fn varint(&mut self, mut value: i64) -> &mut Encoder {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+
+            let done = (value == 0 && byte & 0x40 == 0) ||
+                       (value == -1 && byte & 0x40 != 0);
+
+            self.data.push(if done { byte } else { byte | 0x80 });
+
+            if done {
+                break;
+            }
+        }
+
+        return self;
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Encoder {
         let size = bytes.len();
         if size > 0x3FFFFFFF {
-            self.last_error = Some(Error::new("[bytes] is too long"));
+            self.last_error = Some(Error::new(ErrorKind::SizeTooLarge));
             return self;
         }
-        let mut sref = self.size(size);
-        sref.data.extend_from_slice(bytes);
-        return sref;
+        self.size(size);
+        self.data.extend_from_slice(bytes);
+        return self;
     }
 
-    pub fn string(mut self, string: &str) -> Encoder {
+    pub fn string(&mut self, string: &str) -> &mut Encoder {
         let size = string.len();
         if size > 0x3FFFFFFF {
-            self.last_error = Some(Error::new("[string] is too long"));
+            self.last_error = Some(Error::new(ErrorKind::SizeTooLarge));
             return self;
         }
-        let mut sref = self.size(size);
-        sref.data.extend_from_slice(string.as_bytes());
-        return sref;
+        self.size(size);
+        self.data.extend_from_slice(string.as_bytes());
+        return self;
     }
 
-    pub fn end(self) -> Result<Vec<u8>, Error> {
-        match self.last_error {
-            Some(error) => Err(error),
-            None                => Ok(self.data),
+    /// Drains the accumulated bytes out of this `Encoder` without consuming
+    /// it, so it can be `reset` and reused for the next message.
+    pub fn finish(&mut self) -> Result<Vec<u8>, Error> {
+        match self.last_error.take() {
+            Some(error) => {
+                // Drop the partial buffer along with the error so a second
+                // `finish()` call without an intervening `reset()` can't
+                // observe the stale data from before the failure.
+                self.data.clear();
+                Err(error)
+            }
+            None => Ok(mem::replace(&mut self.data, Vec::new())),
         }
     }
+
+    /// Consumes the `Encoder` and returns its accumulated bytes, for the
+    /// common case of building one message and discarding the builder.
+    pub fn into_bytes(mut self) -> Result<Vec<u8>, Error> {
+        self.finish()
+    }
 }
 
 pub struct Decoder {
@@ -181,7 +333,7 @@ impl Decoder {
     pub fn uint8(&self) -> Result<u8, Error> {
         let index = self.index.get();
         if index >= self.length {
-            return Err(Error::out_of_bounds());
+            return Err(Error::out_of_bounds(index));
         }
         let uint8 = self.data[index];
         self.index.set(index + 1);
@@ -224,6 +376,18 @@ impl Decoder {
         Ok(unsafe { mem::transmute_copy(&uint32) })
     }
 
+    pub fn uint64(&self) -> Result<u64, Error> {
+        Ok(
+            (try!(self.uint32()) as u64) << 32 |
+            (try!(self.uint32()) as u64)
+        )
+    }
+
+    pub fn int64(&self) -> Result<i64, Error> {
+        let uint64 = try!(self.uint64());
+        Ok(unsafe { mem::transmute_copy(&uint64) })
+    }
+
     pub fn float64(&self) -> Result<f64, Error> {
         let uint64 = (try!(self.uint32()) as u64) << 32 |
                                  (try!(self.uint32()) as u64);
@@ -273,11 +437,77 @@ impl Decoder {
         )
     }
 
+    /// Decodes an unsigned LEB128 varint produced by `Encoder::varuint`.
+    pub fn varuint(&self) -> Result<u64, Error> {
+        let mut result: u64 = 0;
+        let mut shift: u32 = 0;
+
+        loop {
+            let byte = try!(self.uint8());
+
+            if shift >= 64 {
+                return Err(Error::at(ErrorKind::VarintOverflow, self.index.get()));
+            }
+
+            // At shift 63 there is room left for exactly one more bit; any
+            // higher bit in this byte's 7-bit payload would be silently
+            // dropped by the shift below instead of rejected.
+            if shift == 63 && (byte & 0x7F) > 1 {
+                return Err(Error::at(ErrorKind::VarintOverflow, self.index.get()));
+            }
+
+            result |= ((byte & 0x7F) as u64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Decodes a signed LEB128 varint produced by `Encoder::varint`.
+    pub fn varint(&self) -> Result<i64, Error> {
+        let mut result: i64 = 0;
+        let mut shift: u32 = 0;
+        let mut byte: u8;
+
+        loop {
+            byte = try!(self.uint8());
+
+            if shift >= 64 {
+                return Err(Error::at(ErrorKind::VarintOverflow, self.index.get()));
+            }
+
+            // At shift 63 there is room left for exactly the sign bit: the
+            // low 7 bits of this byte must be either all zero (positive) or
+            // all one (negative two's-complement padding), otherwise value
+            // bits beyond bit 63 would be silently dropped by the shift.
+            if shift == 63 && byte & 0x7F != 0 && byte & 0x7F != 0x7F {
+                return Err(Error::at(ErrorKind::VarintOverflow, self.index.get()));
+            }
+
+            result |= ((byte & 0x7F) as i64) << shift;
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        if shift < 64 && byte & 0x40 != 0 {
+            result |= -1i64 << shift;
+        }
+
+        Ok(result)
+    }
+
     pub fn bytes(&self) -> Result<Vec<u8>, Error> {
         let size = try!(self.size());
         let index = self.index.get();
         if index + size > self.length {
-            return Err(Error::out_of_bounds());
+            return Err(Error::out_of_bounds(index));
         }
 
         let bytes = self.data[index .. index + size].to_vec();
@@ -289,9 +519,37 @@ impl Decoder {
 
     pub fn string(&self) -> Result<String, Error> {
         let bytes = try!(self.bytes());
+        let payload_start = self.index.get() - bytes.len();
         return match String::from_utf8(bytes) {
             Ok(string) => Ok(string),
-            Err(_) => Err(Error::new("Couldn't decode UTF-8 string")),
+            Err(e) => Err(Error::at(ErrorKind::InvalidUtf8, payload_start + e.utf8_error().valid_up_to())),
+        }
+    }
+
+    /// Like `bytes`, but slices directly into the backing buffer instead of
+    /// allocating a new `Vec`. The returned slice borrows from `self`.
+    pub fn bytes_ref(&self) -> Result<&[u8], Error> {
+        let size = try!(self.size());
+        let index = self.index.get();
+        if index + size > self.length {
+            return Err(Error::out_of_bounds(index));
+        }
+
+        let bytes = &self.data[index .. index + size];
+
+        self.index.set(index + size);
+
+        return Ok(bytes);
+    }
+
+    /// Like `string`, but validates UTF-8 in place and returns a `&str`
+    /// borrowing from `self` instead of allocating a new `String`.
+    pub fn str_ref(&self) -> Result<&str, Error> {
+        let bytes = try!(self.bytes_ref());
+        let payload_start = self.index.get() - bytes.len();
+        return match std::str::from_utf8(bytes) {
+            Ok(string) => Ok(string),
+            Err(e) => Err(Error::at(ErrorKind::InvalidUtf8, payload_start + e.valid_up_to())),
         }
     }
 
@@ -299,3 +557,81 @@ impl Decoder {
         self.index.get() >= self.length
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varuint_roundtrips_extremes() {
+        let mut encoder = Encoder::new();
+        encoder.varuint(0);
+        encoder.varuint(1);
+        encoder.varuint(u64::max_value());
+
+        let decoder = Decoder::new(encoder.into_bytes().unwrap());
+        assert_eq!(decoder.varuint().unwrap(), 0);
+        assert_eq!(decoder.varuint().unwrap(), 1);
+        assert_eq!(decoder.varuint().unwrap(), u64::max_value());
+    }
+
+    #[test]
+    fn varint_roundtrips_extremes() {
+        let mut encoder = Encoder::new();
+        encoder.varint(0);
+        encoder.varint(-1);
+        encoder.varint(i64::min_value());
+        encoder.varint(i64::max_value());
+
+        let decoder = Decoder::new(encoder.into_bytes().unwrap());
+        assert_eq!(decoder.varint().unwrap(), 0);
+        assert_eq!(decoder.varint().unwrap(), -1);
+        assert_eq!(decoder.varint().unwrap(), i64::min_value());
+        assert_eq!(decoder.varint().unwrap(), i64::max_value());
+    }
+
+    #[test]
+    fn varuint_rejects_bit_64_overflow() {
+        // 9 continuation bytes of all-ones followed by a terminating byte
+        // whose payload bits don't fit in the one bit of width left at
+        // shift 63. This used to silently decode as `Ok(u64::MAX)` instead
+        // of being rejected.
+        let mut bytes = vec![0xFFu8; 9];
+        bytes.push(0x7F);
+
+        let decoder = Decoder::new(bytes);
+        match decoder.varuint() {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::VarintOverflow),
+            Ok(value) => panic!("expected VarintOverflow, got Ok({})", value),
+        }
+    }
+
+    #[test]
+    fn varint_rejects_11_byte_stream() {
+        // No byte in this stream ever clears its continuation bit, so the
+        // 64-bit width is blown before the varint can terminate.
+        let bytes = vec![0xFFu8; 11];
+
+        let decoder = Decoder::new(bytes);
+        match decoder.varint() {
+            Err(e) => assert_eq!(e.kind(), ErrorKind::VarintOverflow),
+            Ok(value) => panic!("expected VarintOverflow, got Ok({})", value),
+        }
+    }
+
+    #[test]
+    fn finish_does_not_leak_stale_buffer_after_error() {
+        let mut encoder = Encoder::new();
+        encoder.uint8(42);
+        encoder.uint8(43);
+
+        let oversized = String::from_utf8(vec![b'a'; 0x40000000]).unwrap();
+        encoder.string(&oversized);
+
+        assert!(encoder.finish().is_err());
+
+        // A second `finish()` with no intervening `reset()` must not hand
+        // back the partial buffer from before the failure.
+        assert_eq!(encoder.finish().unwrap(), Vec::<u8>::new());
+    }
+}